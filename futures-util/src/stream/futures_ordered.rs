@@ -0,0 +1,181 @@
+//! An unbounded queue of futures whose items are yielded in the order the
+//! futures were submitted, regardless of which one finishes first.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+use std::iter::FromIterator;
+use std::mem::PinMut;
+
+use futures_core::future::Future;
+use futures_core::stream::Stream;
+use futures_core::task::{self, Poll};
+
+use super::FuturesUnordered;
+
+/// Wraps a future so that it resolves to `(seq, output)`, tagging it with
+/// the order it was pushed into a `FuturesOrdered`.
+struct OrderWrapper<F> {
+    data: F,
+    seq: usize,
+}
+
+impl<F: Future> Future for OrderWrapper<F> {
+    type Output = (usize, F::Output);
+
+    fn poll(self: PinMut<Self>, cx: &mut task::Context) -> Poll<Self::Output> {
+        // Safety: we never move `data` out of `self`, so projecting a
+        // pinned reference to it from our own pinned reference is sound.
+        let this = unsafe { PinMut::get_mut_unchecked(self) };
+        let data = unsafe { PinMut::new_unchecked(&mut this.data) };
+        data.poll(cx).map(|output| (this.seq, output))
+    }
+}
+
+/// An unbounded queue of futures which yields their outputs in the order
+/// the futures were submitted, not the order they complete.
+///
+/// This structure is optimized to manage a large number of futures.
+/// Futures managed by `FuturesOrdered` are polled concurrently, exactly
+/// like the ones in a `FuturesUnordered`, and will only be polled when
+/// they generate notifications. Unlike `FuturesUnordered`, a future that
+/// finishes out of turn has its output held until every future submitted
+/// before it has also finished and been yielded.
+///
+/// `FuturesOrdered` can be filled by `collect`ing an iterator of `Future`s
+/// into a `FuturesOrdered`, or by `push`ing `Future`s onto an existing
+/// `FuturesOrdered`. When new `Future`s are added, `poll_next` must be
+/// called in order to begin receiving wakeups for new `Future`s.
+#[must_use = "streams do nothing unless polled"]
+pub struct FuturesOrdered<F: Future> {
+    in_progress: FuturesUnordered<OrderWrapper<F>>,
+    queued_outputs: BTreeMap<usize, F::Output>,
+    next_to_emit: usize,
+    next_to_push: usize,
+}
+
+impl<F: Future> Unpin for FuturesOrdered<F> {}
+
+impl<F: Future> FuturesOrdered<F> {
+    /// Constructs a new, empty `FuturesOrdered`.
+    ///
+    /// The returned `FuturesOrdered` does not contain any futures.
+    /// In this state, `FuturesOrdered::poll_next` will return
+    /// `Poll::Ready(None)`.
+    pub fn new() -> FuturesOrdered<F> {
+        FuturesOrdered {
+            in_progress: FuturesUnordered::new(),
+            queued_outputs: BTreeMap::new(),
+            next_to_emit: 0,
+            next_to_push: 0,
+        }
+    }
+
+    /// Returns the number of futures contained in the queue.
+    ///
+    /// This represents the total number of in-flight futures, including
+    /// both those still being polled and those already finished but
+    /// buffered while waiting for their turn to be yielded.
+    pub fn len(&self) -> usize {
+        // `FuturesUnordered::len` already masks out its internal
+        // "terminated" sentinel, so this stays a plain, non-overflowing
+        // count even once `in_progress` has run dry.
+        self.in_progress.len() + self.queued_outputs.len()
+    }
+
+    /// Returns `true` if the queue contains no futures.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Push a future into the queue.
+    ///
+    /// This function submits the given future to the queue for managing.
+    /// Its output will be yielded in the order it was pushed in, relative
+    /// to the other futures in the queue, regardless of which one
+    /// completes first. This function will not call `poll` on the
+    /// submitted future. The caller must ensure that
+    /// `FuturesOrdered::poll_next` is called in order to receive task
+    /// notifications.
+    pub fn push(&mut self, future: F) {
+        let wrapped = OrderWrapper {
+            data: future,
+            seq: self.next_to_push,
+        };
+        self.next_to_push += 1;
+        self.in_progress.push(wrapped);
+    }
+}
+
+impl<F: Future> Default for FuturesOrdered<F> {
+    fn default() -> FuturesOrdered<F> {
+        FuturesOrdered::new()
+    }
+}
+
+impl<F: Future> Stream for FuturesOrdered<F> {
+    type Item = F::Output;
+
+    fn poll_next(mut self: PinMut<Self>, cx: &mut task::Context)
+        -> Poll<Option<Self::Item>>
+    {
+        // If the next-in-line output already finished and is sitting in
+        // the reorder buffer, we can hand it back without touching the
+        // inner set at all.
+        if let Some(output) = self.queued_outputs.remove(&self.next_to_emit) {
+            self.next_to_emit += 1;
+            return Poll::Ready(Some(output));
+        }
+
+        loop {
+            match PinMut::new(&mut self.in_progress).poll_next(cx) {
+                Poll::Ready(Some((seq, output))) => {
+                    if seq == self.next_to_emit {
+                        self.next_to_emit += 1;
+                        return Poll::Ready(Some(output));
+                    }
+
+                    // This future resolved ahead of its turn; hold its
+                    // output until every future pushed before it has also
+                    // completed and been yielded.
+                    self.queued_outputs.insert(seq, output);
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<F: Future> Debug for FuturesOrdered<F> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "FuturesOrdered {{ ... }}")
+    }
+}
+
+impl<F: Future> FromIterator<F> for FuturesOrdered<F> {
+    fn from_iter<T>(iter: T) -> Self
+    where
+        T: IntoIterator<Item = F>,
+    {
+        let acc = FuturesOrdered::new();
+        iter.into_iter().fold(acc, |mut acc, item| { acc.push(item); acc })
+    }
+}
+
+/// Converts a list of futures into a `Stream` of results from the futures,
+/// yielded in the order the futures were provided in.
+///
+/// This function will take a list of futures (e.g. a vector, an iterator,
+/// etc), and return a stream. The stream will yield the items in the order
+/// they were given, waiting for earlier futures to complete before yielding
+/// the output of a later one that finished first.
+///
+/// Note that the returned queue can also be used to dynamically push more
+/// futures into it as they become available.
+pub fn futures_ordered<I>(futures: I) -> FuturesOrdered<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    futures.into_iter().collect()
+}
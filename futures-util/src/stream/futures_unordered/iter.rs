@@ -0,0 +1,154 @@
+use std::marker::PhantomData;
+use std::mem::PinMut;
+
+use super::Node;
+use super::FuturesUnordered;
+
+/// Immutable iterator over all futures in the unordered set.
+#[derive(Debug)]
+pub struct Iter<'a, F: 'a>(pub(super) IterPinRef<'a, F>);
+
+/// Mutable iterator over all futures in the unordered set.
+#[derive(Debug)]
+pub struct IterMut<'a, F: 'a>(pub(super) IterPinMut<'a, F>);
+
+/// Owned iterator over all futures in the unordered set.
+#[derive(Debug)]
+pub struct IntoIter<F> {
+    pub(super) len: usize,
+    pub(super) inner: FuturesUnordered<F>,
+}
+
+/// Immutable, pinned iterator over all futures in the unordered set.
+#[derive(Debug)]
+pub struct IterPinRef<'a, F: 'a> {
+    pub(super) task: *const Node<F>,
+    pub(super) len: usize,
+    pub(super) _marker: PhantomData<&'a FuturesUnordered<F>>,
+}
+
+/// Mutable, pinned iterator over all futures in the unordered set.
+#[derive(Debug)]
+pub struct IterPinMut<'a, F: 'a> {
+    pub(super) task: *const Node<F>,
+    pub(super) len: usize,
+    pub(super) _marker: PhantomData<&'a mut FuturesUnordered<F>>,
+}
+
+impl<'a, F> Iterator for IterPinMut<'a, F> {
+    type Item = PinMut<'a, F>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.task.is_null() {
+            return None;
+        }
+
+        unsafe {
+            let future = (*(*self.task).future.get()).as_mut().unwrap();
+            let future = PinMut::new_unchecked(future);
+            let next = *(*self.task).next_all.get();
+            self.task = next;
+            self.len -= 1;
+            Some(future)
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a, F> ExactSizeIterator for IterPinMut<'a, F> {}
+
+impl<'a, F: Unpin> Iterator for IterMut<'a, F> {
+    type Item = &'a mut F;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(PinMut::into_mut)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<'a, F: Unpin> ExactSizeIterator for IterMut<'a, F> {}
+
+impl<'a, F> Iterator for IterPinRef<'a, F> {
+    type Item = &'a F;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.task.is_null() {
+            return None;
+        }
+
+        unsafe {
+            // Safety: we only ever hand out a shared reference, so there is
+            // no way to move the future out from under its pinned storage.
+            let future = (*(*self.task).future.get()).as_ref().unwrap();
+            let next = *(*self.task).next_all.get();
+            self.task = next;
+            self.len -= 1;
+            Some(future)
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a, F> ExactSizeIterator for IterPinRef<'a, F> {}
+
+impl<'a, F> Iterator for Iter<'a, F> {
+    type Item = &'a F;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<'a, F> ExactSizeIterator for Iter<'a, F> {}
+
+impl<F> Iterator for IntoIter<F> {
+    type Item = F;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let (head, _) = self.inner.scheduler.head_and_len();
+        // `self.len` is only a size hint; the all-nodes list is the source
+        // of truth for whether there's anything left to yield, so bail out
+        // on a null head even in release builds rather than trusting it.
+        if head.is_null() {
+            self.len = 0;
+            return None;
+        }
+        self.len -= 1;
+
+        // Safety: `head` is a valid pointer into `self.inner`'s linked list,
+        // and we own `self.inner` so nothing else can be racing with us.
+        unsafe {
+            let node = self.inner.scheduler.unlink(head);
+            let future = (*node.future.get()).take()
+                .expect("future already taken");
+            // Hand the node back through the same teardown path `Drop` uses,
+            // so a node still referenced by an outstanding waker is released
+            // rather than double-freed.
+            self.inner.scheduler.release_node(node);
+            Some(future)
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<F> ExactSizeIterator for IntoIter<F> {}
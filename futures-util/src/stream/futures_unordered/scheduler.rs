@@ -0,0 +1,363 @@
+//! The generic "poll only what was woken" scheduling core beneath
+//! `FuturesUnordered`.
+//!
+//! This owns the two linked lists that make the set efficient: the
+//! thread-local `head_all` list of every node currently managed, and the
+//! intrusive MPSC ready-to-run queue of nodes that have been woken since
+//! they were last polled. It is parameterized over `U`, the value stored
+//! in each node, rather than tied to `Future`/`Stream`, so the same
+//! wakeup-minimizing machinery can back other "poll only the woken tasks"
+//! schedulers (e.g. a current-thread executor) without duplicating the
+//! unsafe intrusive-queue code.
+
+use std::cell::UnsafeCell;
+use std::marker::Unpin;
+use std::mem::{self, PinMut};
+use std::ptr;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicPtr};
+use std::sync::{Arc, Weak};
+use std::usize;
+
+use futures_core::task::{self, Poll};
+
+use crate::task::AtomicWaker;
+
+use super::node::Node;
+use super::ready_to_run_queue::{Dequeue, ReadyToRunQueue};
+
+/// The outcome of one call to `Scheduler::tick`.
+pub(super) enum Tick<R> {
+    /// The caller-supplied `poll` closure ran and completed, producing
+    /// this output.
+    Output(R),
+    /// The caller-supplied `poll` closure ran but reported that it is not
+    /// done yet; its node has been re-linked into the set.
+    Yielded,
+    /// The poll budget was exhausted before we got to polling the node we
+    /// dequeued, so it was put back without being touched.
+    YieldedBudget,
+    /// The ready-to-run queue is empty right now, but the scheduler still
+    /// owns live nodes that may become ready later.
+    Idle,
+    /// The ready-to-run queue is empty and the scheduler has no nodes left
+    /// at all.
+    Empty,
+    /// The ready-to-run queue observed an inconsistent state; the caller
+    /// should wake itself and try again later.
+    Inconsistent,
+}
+
+pub(super) struct Scheduler<U> {
+    pub(super) ready_to_run_queue: Arc<ReadyToRunQueue<U>>,
+    pub(super) len: usize,
+    pub(super) head_all: *const Node<U>,
+}
+
+unsafe impl<U: Send> Send for Scheduler<U> {}
+unsafe impl<U: Sync> Sync for Scheduler<U> {}
+impl<U> Unpin for Scheduler<U> {}
+
+impl<U> Scheduler<U> {
+    pub(super) fn new() -> Self {
+        let stub = Arc::new(Node {
+            future: UnsafeCell::new(None),
+            next_all: UnsafeCell::new(ptr::null()),
+            prev_all: UnsafeCell::new(ptr::null()),
+            next_ready_to_run: AtomicPtr::new(ptr::null_mut()),
+            queued: AtomicBool::new(true),
+            ready_to_run_queue: Weak::new(),
+        });
+        let stub_ptr = &*stub as *const Node<U>;
+        let ready_to_run_queue = Arc::new(ReadyToRunQueue {
+            parent: AtomicWaker::new(),
+            head: AtomicPtr::new(stub_ptr as *mut _),
+            tail: UnsafeCell::new(stub_ptr),
+            stub,
+        });
+
+        Scheduler {
+            len: 0,
+            head_all: ptr::null_mut(),
+            ready_to_run_queue,
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        // `usize::MAX` is the "already terminated" sentinel, not a real
+        // count; mask it back down to 0 so callers never observe it.
+        if self.len == usize::MAX { 0 } else { self.len }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.len == 0 || self.len == usize::MAX
+    }
+
+    /// Ensure the parent task is registered to be woken when any node in
+    /// this scheduler is notified.
+    pub(super) fn register(&self, waker: &task::Waker) {
+        self.ready_to_run_queue.parent.register(waker);
+    }
+
+    /// Push a new item into the set.
+    ///
+    /// This does not poll `item`; the caller must drive `tick` to start
+    /// receiving wakeups for it.
+    pub(super) fn push(&mut self, item: U) {
+        let node = Arc::new(Node {
+            future: UnsafeCell::new(Some(item)),
+            next_all: UnsafeCell::new(ptr::null_mut()),
+            prev_all: UnsafeCell::new(ptr::null_mut()),
+            next_ready_to_run: AtomicPtr::new(ptr::null_mut()),
+            queued: AtomicBool::new(true),
+            ready_to_run_queue: Arc::downgrade(&self.ready_to_run_queue),
+        });
+
+        // Right now our node has a strong reference count of 1. We transfer
+        // ownership of this reference count to our internal linked list
+        // and we'll reclaim ownership through the `unlink` function below.
+        let ptr = self.link(node);
+
+        // We'll need to get the item "into the system" to start tracking
+        // it, e.g. getting its unpark notifications going to us tracking
+        // which nodes are ready. To do that we unconditionally enqueue it
+        // for polling here.
+        self.ready_to_run_queue.enqueue(ptr);
+    }
+
+    /// Dequeue a single ready node and hand it to `poll`, re-linking it if
+    /// `poll` reports it isn't done yet. This is the only place the
+    /// intrusive ready-to-run queue's unsafety is exercised.
+    pub(super) fn tick<F, R>(&mut self, cx: &mut task::Context, budget: &mut usize, poll: F) -> Tick<R>
+    where
+        F: FnOnce(PinMut<U>, &mut task::Context) -> Poll<R>,
+    {
+        loop {
+            // Safety: &mut self guarantees the mutual exclusion `dequeue`
+            // expects
+            let node = match unsafe { self.ready_to_run_queue.dequeue() } {
+                Dequeue::Empty => {
+                    // `is_empty` treats the "already terminated" sentinel
+                    // as empty, so a set that was already driven to
+                    // completion keeps reporting `Tick::Empty` (and thus
+                    // `Poll::Ready(None)`) on every subsequent call instead
+                    // of getting stuck reporting `Tick::Idle` forever.
+                    return if self.is_empty() { Tick::Empty } else { Tick::Idle };
+                }
+                Dequeue::Inconsistent => return Tick::Inconsistent,
+                Dequeue::Data(node) => node,
+            };
+
+            debug_assert!(node != self.ready_to_run_queue.stub());
+
+            // Safety:
+            // - Node is a valid pointer.
+            // - We are the only thread that accesses the `UnsafeCell` that
+            //   contains the item
+            let item = match unsafe { &mut *(*node).future.get() } {
+                Some(item) => item,
+
+                // If the item has already gone away then we're just
+                // cleaning out this node. See the comment in
+                // `release_node` for more information, but we're basically
+                // just taking ownership of our reference count here.
+                None => {
+                    // Safety: `node` is a valid pointer
+                    let node = unsafe { Arc::from_raw(node) };
+
+                    // Double check that the call to `release_node` really
+                    // happened. Calling it required the node to be unlinked.
+                    unsafe {
+                        debug_assert!((*node.next_all.get()).is_null());
+                        debug_assert!((*node.prev_all.get()).is_null());
+                    }
+                    continue
+                }
+            };
+
+            if *budget == 0 {
+                // We've polled enough for this call. Put the node back
+                // without touching it; it hasn't been polled, so it must
+                // stay enqueued for a future tick.
+                self.ready_to_run_queue.enqueue(node);
+                return Tick::YieldedBudget;
+            }
+            *budget -= 1;
+
+            // Safety: `node` is a valid pointer
+            let node = unsafe { self.unlink(node) };
+
+            // Unset queued flag... this must be done before
+            // polling. This ensures that the item gets
+            // rescheduled if it is notified **during** a call
+            // to `poll`.
+            let prev = node.queued.swap(false, SeqCst);
+            assert!(prev);
+
+            let local_waker = node.local_waker();
+
+            // We're going to need to be very careful if the `poll`
+            // function below panics. We need to (a) not leak memory and
+            // (b) ensure that we still don't have any use-after-frees. To
+            // manage this we do a few things:
+            //
+            // * A "bomb" is created which if dropped abnormally will call
+            //   `release_node`. That way we'll be sure the memory management
+            //   of the `node` is managed correctly. In particular
+            //   `release_node` will drop the item. This ensures that it is
+            //   dropped on this thread and not accidentally on a different
+            //   thread (bad).
+            // * We unlink the node from our internal queue to preemptively
+            //   assume it'll panic, in which case we'll want to discard it
+            //   regardless.
+            struct Bomb<'a, U: 'a> {
+                scheduler: &'a mut Scheduler<U>,
+                node: Option<Arc<Node<U>>>,
+            }
+
+            impl<'a, U> Drop for Bomb<'a, U> {
+                fn drop(&mut self) {
+                    if let Some(node) = self.node.take() {
+                        self.scheduler.release_node(node);
+                    }
+                }
+            }
+
+            let mut bomb = Bomb {
+                node: Some(node),
+                scheduler: self,
+            };
+
+            // Safety: We won't move the item ever again
+            let item = unsafe { PinMut::new_unchecked(item) };
+
+            let mut cx = cx.with_waker(&local_waker);
+            let res = poll(item, &mut cx);
+
+            return match res {
+                Poll::Pending => {
+                    let node = bomb.node.take().unwrap();
+                    bomb.scheduler.link(node);
+                    Tick::Yielded
+                }
+                Poll::Ready(output) => Tick::Output(output),
+            };
+        }
+    }
+
+    /// Returns an iterator's starting position: the head of the all-nodes
+    /// list plus the current length.
+    pub(super) fn head_and_len(&self) -> (*const Node<U>, usize) {
+        // Mask the sentinel the same way `len`/`is_empty` do: a terminated
+        // scheduler has no nodes left (`head_all` is already null), so its
+        // reported length must be `0`, not `usize::MAX`, or callers like
+        // `ExactSizeIterator::len` would report billions of bogus items.
+        (self.head_all, self.len())
+    }
+
+    /// Releases the node. It destroys the item inside and either drops
+    /// the `Arc<Node>` or transfers ownership to the ready to run queue.
+    /// The node this method is called on must have been unlinked before.
+    pub(super) fn release_node(&mut self, node: Arc<Node<U>>) {
+        // `release_node` must only be called on unlinked nodes
+        unsafe {
+            debug_assert!((*node.next_all.get()).is_null());
+            debug_assert!((*node.prev_all.get()).is_null());
+        }
+
+        // The item is done, try to reset the queued flag. This will prevent
+        // `notify` from doing any work in the future
+        let prev = node.queued.swap(true, SeqCst);
+
+        // Drop the item, even if it hasn't finished yet. This is safe
+        // because we're dropping the item on the thread that owns the
+        // scheduler, which correctly tracks U's lifetimes and such.
+        unsafe {
+            drop((*node.future.get()).take());
+        }
+
+        // If the queued flag was previously set, then it means that this node
+        // is still in our internal ready to run queue. We then transfer
+        // ownership of our reference count to the ready to run queue, and it'll
+        // come along and free it later, noticing that the item is `None`.
+        //
+        // If, however, the queued flag was *not* set then we're safe to
+        // release our reference count on the internal node. The queued flag
+        // was set above so all future `enqueue` operations will not actually
+        // enqueue the node, so our node will never see the ready to run queue
+        // again. The node itself will be deallocated once all reference counts
+        // have been dropped by the various owning tasks elsewhere.
+        if prev {
+            mem::forget(node);
+        }
+    }
+
+    /// Insert a new node into the internal linked list.
+    fn link(&mut self, node: Arc<Node<U>>) -> *const Node<U> {
+        let ptr = Arc::into_raw(node);
+        unsafe {
+            *(*ptr).next_all.get() = self.head_all;
+            if !self.head_all.is_null() {
+                *(*self.head_all).prev_all.get() = ptr;
+            }
+        }
+
+        self.head_all = ptr;
+        self.len += 1;
+        ptr
+    }
+
+    /// Remove the node from the linked list tracking all nodes currently
+    /// managed by this scheduler.
+    /// This function is unsafe because it has be guaranteed that `node` is a
+    /// valid pointer.
+    pub(super) unsafe fn unlink(&mut self, node: *const Node<U>) -> Arc<Node<U>> {
+        let node = Arc::from_raw(node);
+        let next = *node.next_all.get();
+        let prev = *node.prev_all.get();
+        *node.next_all.get() = ptr::null_mut();
+        *node.prev_all.get() = ptr::null_mut();
+
+        if !next.is_null() {
+            *(*next).prev_all.get() = prev;
+        }
+
+        if !prev.is_null() {
+            *(*prev).next_all.get() = next;
+        } else {
+            self.head_all = next;
+        }
+        self.len -= 1;
+        node
+    }
+}
+
+impl<U> Drop for Scheduler<U> {
+    fn drop(&mut self) {
+        // When a `Scheduler` is dropped we want to drop all items
+        // associated with it. At the same time though there may be tons of
+        // `Task` handles flying around which contain `Node<U>` references
+        // inside them. We'll let those naturally get deallocated when the
+        // `Task` itself goes out of scope or gets notified.
+        unsafe {
+            while !self.head_all.is_null() {
+                let head = self.head_all;
+                let node = self.unlink(head);
+                self.release_node(node);
+            }
+        }
+
+        // Note that at this point we could still have a bunch of nodes in the
+        // ready to run queue. None of those nodes, however, have items
+        // associated with them so they're safe to destroy on any thread. At
+        // this point the `Scheduler`, the owner of the one strong reference
+        // to the ready to run queue will drop the strong reference. At that
+        // point whichever thread releases the strong refcount last (be it
+        // this thread or some other thread as part of an `upgrade`) will
+        // clear out the ready to run queue and free all remaining nodes.
+        //
+        // While that freeing operation isn't guaranteed to happen here, it's
+        // guaranteed to happen "promptly" as no more "blocking work" will
+        // happen while there's a strong refcount held.
+    }
+}
@@ -0,0 +1,60 @@
+use std::cell::Cell;
+use std::mem::PinMut;
+
+use futures_core::future::Future;
+use futures_core::stream::Stream;
+use futures_core::task::{self, Poll};
+use futures_test::task::noop_context;
+use futures_util::stream::FuturesOrdered;
+
+/// A future that resolves to `value` after `ready_after` polls, re-waking
+/// itself on every `Pending` in between so it stays in the ready queue.
+struct ResolvesAfter<T> {
+    remaining: Cell<u32>,
+    value: Cell<Option<T>>,
+}
+
+impl<T> ResolvesAfter<T> {
+    fn new(ready_after: u32, value: T) -> Self {
+        ResolvesAfter {
+            remaining: Cell::new(ready_after),
+            value: Cell::new(Some(value)),
+        }
+    }
+}
+
+impl<T> Future for ResolvesAfter<T> {
+    type Output = T;
+
+    fn poll(self: PinMut<Self>, cx: &mut task::Context) -> Poll<T> {
+        if self.remaining.get() == 0 {
+            Poll::Ready(self.value.take().unwrap())
+        } else {
+            self.remaining.set(self.remaining.get() - 1);
+            cx.waker().wake();
+            Poll::Pending
+        }
+    }
+}
+
+#[test]
+fn yields_outputs_in_submission_order_even_when_they_finish_out_of_order() {
+    let mut ordered = FuturesOrdered::new();
+    // Pushed in order a, b, c, but b finishes first (0 extra polls), then
+    // a and c each need one more poll before they're ready.
+    ordered.push(ResolvesAfter::new(1, "a"));
+    ordered.push(ResolvesAfter::new(0, "b"));
+    ordered.push(ResolvesAfter::new(1, "c"));
+
+    let mut cx = noop_context();
+    let mut out = Vec::new();
+    loop {
+        match PinMut::new(&mut ordered).poll_next(&mut cx) {
+            Poll::Ready(Some(item)) => out.push(item),
+            Poll::Ready(None) => break,
+            Poll::Pending => panic!("nothing left to wait on in this test"),
+        }
+    }
+
+    assert_eq!(out, vec!["a", "b", "c"]);
+}
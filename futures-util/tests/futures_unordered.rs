@@ -0,0 +1,192 @@
+use std::cell::Cell;
+use std::mem::PinMut;
+use std::rc::Rc;
+
+use futures_core::future::Future;
+use futures_core::stream::{Stream, FusedStream};
+use futures_core::task::{self, Poll};
+use futures_test::task::noop_context;
+use futures_util::stream::FuturesUnordered;
+
+/// A future that re-wakes itself and reports `Pending` forever.
+struct Never;
+
+impl Future for Never {
+    type Output = ();
+
+    fn poll(self: PinMut<Self>, cx: &mut task::Context) -> Poll<()> {
+        cx.waker().wake();
+        Poll::Pending
+    }
+}
+
+#[test]
+fn poll_next_yields_instead_of_spinning_forever() {
+    // Before the yield budget, a set of futures that keep re-waking
+    // themselves without ever completing would make `poll_next` spin
+    // forever inside a single call. If this test hangs, the budget isn't
+    // being enforced.
+    let mut unordered = FuturesUnordered::new();
+    for _ in 0..256 {
+        unordered.push(Never);
+    }
+
+    let mut cx = noop_context();
+    assert_eq!(PinMut::new(&mut unordered).poll_next(&mut cx), Poll::Pending);
+    // None of the futures ever complete, so the set must still hold all of
+    // them after the bounded call returned.
+    assert_eq!(unordered.len(), 256);
+}
+
+/// A future that completes with `()` the second time it is polled.
+#[derive(Debug, PartialEq)]
+struct Twice {
+    polled: Cell<bool>,
+}
+
+impl Twice {
+    fn new() -> Self {
+        Twice { polled: Cell::new(false) }
+    }
+}
+
+impl Future for Twice {
+    type Output = ();
+
+    fn poll(self: PinMut<Self>, cx: &mut task::Context) -> Poll<()> {
+        if self.polled.replace(true) {
+            Poll::Ready(())
+        } else {
+            cx.waker().wake();
+            Poll::Pending
+        }
+    }
+}
+
+#[test]
+fn is_terminated_transitions_and_resets_on_push() {
+    let mut unordered = FuturesUnordered::<Twice>::new();
+    let mut cx = noop_context();
+
+    // An empty set is not terminated until it has actually been polled to
+    // `Ready(None)` once.
+    assert!(!unordered.is_terminated());
+    assert_eq!(PinMut::new(&mut unordered).poll_next(&mut cx), Poll::Ready(None));
+    assert!(unordered.is_terminated());
+
+    // Pushing into a terminated set must resurrect it.
+    unordered.push(Twice::new());
+    assert!(!unordered.is_terminated());
+    assert_eq!(unordered.len(), 1);
+
+    // With one future in the set the poll budget is a single poll, which
+    // `Twice` spends on its first, self-waking poll, so this call yields.
+    assert_eq!(PinMut::new(&mut unordered).poll_next(&mut cx), Poll::Pending);
+    assert_eq!(PinMut::new(&mut unordered).poll_next(&mut cx), Poll::Ready(Some(())));
+    assert!(!unordered.is_terminated());
+    assert_eq!(PinMut::new(&mut unordered).poll_next(&mut cx), Poll::Ready(None));
+    assert!(unordered.is_terminated());
+    // Polling an already-terminated set again must keep reporting `None`,
+    // not get stuck returning `Pending`.
+    assert_eq!(PinMut::new(&mut unordered).poll_next(&mut cx), Poll::Ready(None));
+}
+
+#[test]
+fn iterators_report_an_accurate_len_after_exhaustion() {
+    let mut unordered = FuturesUnordered::<Twice>::new();
+    unordered.push(Twice::new());
+
+    let mut cx = noop_context();
+    // The single-poll budget is spent on `Twice`'s first poll.
+    assert_eq!(PinMut::new(&mut unordered).poll_next(&mut cx), Poll::Pending);
+    assert_eq!(PinMut::new(&mut unordered).poll_next(&mut cx), Poll::Ready(Some(())));
+    assert_eq!(PinMut::new(&mut unordered).poll_next(&mut cx), Poll::Ready(None));
+    assert!(unordered.is_terminated());
+
+    // The bug: `iter`/`iter_mut`/`into_iter` built off the raw, unmasked
+    // `len` field reported `usize::MAX` remaining items on a terminated set.
+    assert_eq!(unordered.iter().len(), 0);
+    assert_eq!(unordered.iter().next(), None);
+    assert_eq!(unordered.iter_mut().len(), 0);
+    assert_eq!(unordered.iter_mut().next(), None);
+    assert_eq!(unordered.into_iter().len(), 0);
+}
+
+#[test]
+fn scheduler_delivers_every_pushed_future_exactly_once() {
+    // A basic sanity check that the generic `Scheduler` core still drives
+    // a mixed batch of immediately-ready and multi-poll futures to
+    // completion exactly once each, with none lost or duplicated.
+    let mut unordered = FuturesUnordered::new();
+    for i in 0..64u32 {
+        unordered.push(ResolvesAfter::new(i % 3, i));
+    }
+
+    let mut cx = noop_context();
+    let mut seen = Vec::new();
+    loop {
+        match PinMut::new(&mut unordered).poll_next(&mut cx) {
+            Poll::Ready(Some(value)) => seen.push(value),
+            Poll::Ready(None) => break,
+            Poll::Pending => continue,
+        }
+    }
+
+    seen.sort();
+    assert_eq!(seen, (0..64u32).collect::<Vec<_>>());
+}
+
+/// A future that resolves to `value` after `ready_after` polls, re-waking
+/// itself on every `Pending` in between so it stays in the ready queue.
+struct ResolvesAfter<T> {
+    remaining: Cell<u32>,
+    value: Cell<Option<T>>,
+}
+
+impl<T> ResolvesAfter<T> {
+    fn new(ready_after: u32, value: T) -> Self {
+        ResolvesAfter {
+            remaining: Cell::new(ready_after),
+            value: Cell::new(Some(value)),
+        }
+    }
+}
+
+impl<T> Future for ResolvesAfter<T> {
+    type Output = T;
+
+    fn poll(self: PinMut<Self>, cx: &mut task::Context) -> Poll<T> {
+        if self.remaining.get() == 0 {
+            Poll::Ready(self.value.take().unwrap())
+        } else {
+            self.remaining.set(self.remaining.get() - 1);
+            cx.waker().wake();
+            Poll::Pending
+        }
+    }
+}
+
+#[test]
+fn spawn_obj_drives_a_pushed_future() {
+    use futures_core::future::FutureObj;
+    use futures_core::task::Spawn;
+
+    struct SetFlag(Rc<Cell<bool>>);
+
+    impl Future for SetFlag {
+        type Output = ();
+
+        fn poll(self: PinMut<Self>, _cx: &mut task::Context) -> Poll<()> {
+            self.0.set(true);
+            Poll::Ready(())
+        }
+    }
+
+    let ran = Rc::new(Cell::new(false));
+    let mut pool: FuturesUnordered<FutureObj<'static, ()>> = FuturesUnordered::new();
+    pool.spawn_obj(FutureObj::new(Box::new(SetFlag(ran.clone())))).unwrap();
+
+    let mut cx = noop_context();
+    assert_eq!(PinMut::new(&mut pool).poll_next(&mut cx), Poll::Ready(Some(())));
+    assert!(ran.get());
+}